@@ -5,17 +5,44 @@
 // Please see the LICENSE file in this distribution for license details.
 // ------------------------------------------------------------------------------------------------
 
+use std::collections::HashMap;
 use std::ffi::c_char;
 use std::ffi::c_void;
 
 use mlua::Lua;
 use tree_sitter::Tree;
 
+/// The languages that have been registered with a [`Lua`] instance via
+/// [`Module::register_language`], keyed by the name scripts use to ask for them.  Stored as
+/// mlua app-data so that it's reachable from inside the [`Parser`] userdata's methods.
+type LanguageRegistry = HashMap<String, tree_sitter::Language>;
+
 /// An extension trait that lets you load the `ltreesitter` module into a Lua environment.
 pub trait Module {
     /// Loads the `ltreesitter` module into a Lua environment.  If `global` is true, sets the
     /// global `ltreesitter` variable to the loaded module.
     fn open_ltreesitter(&mut self, global: bool) -> Result<(), mlua::Error>;
+
+    /// Registers a [`tree_sitter::Language`] under `name`, so that a [`Parser`] created via
+    /// [`Module::parser`] can find it by that name.
+    fn register_language(&self, name: impl Into<String>, language: tree_sitter::Language);
+
+    /// Creates a [`Parser`] for the language registered under `name`; the name is looked up each
+    /// time the parser's `parse` method runs.  Push it to Lua (it implements [`mlua::UserData`])
+    /// so scripts can parse source text themselves, e.g. `lua.globals().set("parser",
+    /// lua.parser("python"))` from Rust, then `tree = parser:parse(src)` in Lua.  The
+    /// `ltreesitter` module itself has no function that creates a parser.
+    fn parser(&self, name: impl Into<String>) -> Parser;
+
+    /// Compiles `source` as a tree-sitter S-expression query against `language`, returning a
+    /// [`CompiledQuery`] that can be pushed to Lua and run against any tree of that language via
+    /// [`CompiledQuery::matches`]/[`CompiledQuery::captures`].  Query compilation errors (offset
+    /// plus message) are surfaced as an [`mlua::Error::RuntimeError`].
+    fn compile_query(
+        &self,
+        language: &tree_sitter::Language,
+        source: &str,
+    ) -> Result<CompiledQuery, mlua::Error>;
 }
 
 impl Module for Lua {
@@ -37,6 +64,116 @@ impl Module for Lua {
         load.call((global,))?;
         Ok(())
     }
+
+    fn register_language(&self, name: impl Into<String>, language: tree_sitter::Language) {
+        if self.app_data_ref::<LanguageRegistry>().is_none() {
+            self.set_app_data(LanguageRegistry::new());
+        }
+        let mut registry = self
+            .app_data_mut::<LanguageRegistry>()
+            .expect("registry was just inserted");
+        registry.insert(name.into(), language);
+    }
+
+    fn parser(&self, name: impl Into<String>) -> Parser {
+        Parser {
+            language: name.into(),
+        }
+    }
+
+    fn compile_query(
+        &self,
+        language: &tree_sitter::Language,
+        source: &str,
+    ) -> Result<CompiledQuery, mlua::Error> {
+        let query = tree_sitter::Query::new(language, source).map_err(|err| {
+            mlua::Error::RuntimeError(format!(
+                "query error at byte {}: {}",
+                err.offset, err.message
+            ))
+        })?;
+        Ok(CompiledQuery { query })
+    }
+}
+
+/// A tree produced by [`Parser::parse`], owning its source and the name of the language it was
+/// parsed with (so `edit` can rebuild a matching [`tree_sitter::Parser`] on its own).
+pub struct ParsedTree {
+    tree: Tree,
+    src: Vec<u8>,
+    language: String,
+}
+
+impl ParsedTree {
+    /// Looks up `language` in the [`LanguageRegistry`] and builds a fresh [`tree_sitter::Parser`]
+    /// bound to it.
+    fn tree_sitter_parser(l: &Lua, language: &str) -> Result<tree_sitter::Parser, mlua::Error> {
+        let registry = l.app_data_ref::<LanguageRegistry>().ok_or_else(|| {
+            mlua::Error::RuntimeError("no languages have been registered".to_owned())
+        })?;
+        let language = registry.get(language).ok_or_else(|| {
+            mlua::Error::RuntimeError(format!("no language registered under {:?}", language))
+        })?;
+        let mut parser = tree_sitter::Parser::new();
+        parser
+            .set_language(language.clone())
+            .map_err(mlua::Error::external)?;
+        Ok(parser)
+    }
+}
+
+impl mlua::UserData for ParsedTree {
+    fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("root", |l, this, ()| {
+            node_table(l, this.tree.root_node(), &this.src)
+        });
+
+        // Incremental reparse: apply an edit descriptor to this tree, then reparse `new_src`
+        // against the edited tree so tree-sitter can reuse its unchanged subtrees, instead of
+        // discarding and recreating the whole tree from scratch. The old tree is consumed: we
+        // take it (rather than borrow it) so the Lua side can't keep using stale tree after the
+        // edit, the same way `FromTree::take_tree` consumes a native tree.
+        methods.add_function(
+            "edit",
+            |l, (ud, edit, new_src): (mlua::AnyUserData, mlua::Table, mlua::String)| {
+                let this = ud.take::<ParsedTree>()?;
+                let mut parser = ParsedTree::tree_sitter_parser(l, &this.language)?;
+                let new_src = new_src.as_bytes().to_vec();
+                let edited_tree = this
+                    .tree
+                    .with_source(&this.src)
+                    .edit(&mut parser, &read_input_edit(&edit)?, &new_src)?
+                    .tree;
+                Ok(ParsedTree {
+                    tree: edited_tree,
+                    src: new_src,
+                    language: this.language,
+                })
+            },
+        );
+    }
+}
+
+/// A parser bound to a single language that was registered with [`Module::register_language`].
+pub struct Parser {
+    language: String,
+}
+
+impl mlua::UserData for Parser {
+    fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("parse", |l, this, src: mlua::String| {
+            let mut parser = ParsedTree::tree_sitter_parser(l, &this.language)?;
+            let src = src.as_bytes().to_vec();
+            let tree = parser
+                .parse(&src, None)
+                .ok_or_else(|| mlua::Error::RuntimeError("failed to parse source".to_owned()))?;
+            Ok(ParsedTree {
+                tree,
+                src,
+                language: this.language.clone(),
+            })
+        });
+    }
 }
 
 // Replace this with a call to Tree::into_raw once a >0.28.8 release is cut.
@@ -49,6 +186,16 @@ fn tree_into_raw(tree: Tree) -> *mut c_void {
     raw_tree.as_ptr()
 }
 
+// Replace this with a call to Tree::from_raw once a >0.28.8 release is cut.  `raw` must be a
+// valid `TSTree` pointer, previously handed to Lua by `tree_into_raw`, whose ownership is being
+// transferred back to us.
+unsafe fn tree_from_raw(raw: *mut c_void) -> Tree {
+    type RawTree = std::ptr::NonNull<c_void>;
+    let raw_tree = RawTree::new(raw).expect("ltreesitter gave us a null tree pointer");
+    // Pull the same shenanigans as `tree_into_raw`, in reverse.
+    std::mem::transmute(raw_tree)
+}
+
 /// An extension trait that lets you combine a [`tree_sitter::Tree`] with the source code that it
 /// was parsed from.
 pub trait WithSource {
@@ -99,6 +246,583 @@ impl mlua::IntoLua<'_> for TreeWithSource<'_> {
     }
 }
 
+/// An extension trait that lets Rust host code pull a [`tree_sitter::Tree`] back out of a Lua
+/// value, the reverse of [`WithSource::with_source`]/[`mlua::IntoLua`].
+pub trait FromTree {
+    /// Takes ownership of the [`tree_sitter::Tree`] wrapped by this Lua value, consuming the Lua
+    /// side's reference to it; the value is no longer usable as a tree afterwards.
+    fn take_tree(&self, l: &Lua) -> Result<Tree, mlua::Error>;
+}
+
+impl FromTree for mlua::Value<'_> {
+    fn take_tree(&self, l: &Lua) -> Result<Tree, mlua::Error> {
+        unsafe extern "C-unwind" fn take_tree(l: *mut mlua::lua_State) -> i32 {
+            extern "C-unwind" {
+                fn ltreesitter_tree_take(l: *mut mlua::lua_State, idx: i32) -> *mut c_void;
+            }
+            let raw = ltreesitter_tree_take(l, 1);
+            mlua::ffi::lua_pushlightuserdata(l, raw);
+            1
+        }
+        let take = unsafe { l.create_c_function(take_tree) }?;
+        let raw: mlua::LightUserData = take.call(self.clone())?;
+        if raw.0.is_null() {
+            return Err(mlua::Error::FromLuaConversionError {
+                from: "userdata",
+                to: "tree_sitter::Tree",
+                message: Some("value is not an ltreesitter tree".to_owned()),
+            });
+        }
+        Ok(unsafe { tree_from_raw(raw.0) })
+    }
+}
+
+/// A [`tree_sitter::Tree`] recovered from a Lua value via [`FromTree::take_tree`].
+pub struct OwnedTree(pub Tree);
+
+impl<'lua> mlua::FromLua<'lua> for OwnedTree {
+    fn from_lua(value: mlua::Value<'lua>, l: &'lua Lua) -> Result<Self, mlua::Error> {
+        value.take_tree(l).map(OwnedTree)
+    }
+}
+
+/// Borrows just enough of a Lua tree value — the underlying `TSTree` pointer and the source bytes
+/// it was parsed from — to run a native [`tree_sitter::Query`] against it, without disturbing the
+/// Lua side's own copy the way [`FromTree::take_tree`] does. Only native `ltreesitter` trees and
+/// [`ParsedTree`] are supported; a [`ScopedTree`] is not a native tree and querying one fails.
+fn peek_tree_and_source(l: &Lua, value: &mlua::Value) -> Result<(Tree, Vec<u8>), mlua::Error> {
+    // A `ParsedTree` (from `Parser::parse` or its `edit` method) already owns its tree and
+    // source outright; no FFI round-trip needed, just clone them.
+    if let mlua::Value::UserData(ud) = value {
+        if let Ok(parsed) = ud.borrow::<ParsedTree>() {
+            return Ok((parsed.tree.clone(), parsed.src.clone()));
+        }
+    }
+
+    unsafe extern "C-unwind" fn peek(l: *mut mlua::lua_State) -> i32 {
+        extern "C-unwind" {
+            fn ltreesitter_tree_pointer(l: *mut mlua::lua_State, idx: i32) -> *mut c_void;
+            fn ltreesitter_tree_source(
+                l: *mut mlua::lua_State,
+                idx: i32,
+                len: *mut usize,
+            ) -> *const c_char;
+        }
+        let tree = ltreesitter_tree_pointer(l, 1);
+        let mut src_len: usize = 0;
+        let src = ltreesitter_tree_source(l, 1, &mut src_len);
+        mlua::ffi::lua_pushlightuserdata(l, tree);
+        mlua::ffi::lua_pushinteger(l, src_len as mlua::ffi::lua_Integer);
+        mlua::ffi::lua_pushlightuserdata(l, src as *mut c_void);
+        3
+    }
+    let peek = unsafe { l.create_c_function(peek) }?;
+    let (raw_tree, src_len, raw_src): (mlua::LightUserData, usize, mlua::LightUserData) =
+        peek.call(value.clone())?;
+    if raw_tree.0.is_null() || raw_src.0.is_null() {
+        return Err(mlua::Error::RuntimeError(
+            "value is not an ltreesitter tree".to_owned(),
+        ));
+    }
+    // Borrow the Lua-owned tree just long enough to clone a fresh, independently-owned handle
+    // (tree_sitter trees are cheap, refcounted clones), then forget the borrowed one so we don't
+    // double-free the tree Lua still owns; copy the source bytes for the same reason, since
+    // nothing ties their lifetime to ours.
+    let borrowed = unsafe { tree_from_raw(raw_tree.0) };
+    let tree = borrowed.clone();
+    std::mem::forget(borrowed);
+    let src = unsafe { std::slice::from_raw_parts(raw_src.0 as *const u8, src_len).to_vec() };
+    Ok((tree, src))
+}
+
+fn read_point(table: mlua::Table) -> Result<tree_sitter::Point, mlua::Error> {
+    Ok(tree_sitter::Point {
+        row: table.get("row")?,
+        column: table.get("column")?,
+    })
+}
+
+/// Reads an edit descriptor table (`{start_byte, old_end_byte, new_end_byte, start_point,
+/// old_end_point, new_end_point}`, with each `*_point` a `{row, column}` table) into the
+/// [`tree_sitter::InputEdit`] that [`tree_sitter::Tree::edit`] expects.
+fn read_input_edit(edit: &mlua::Table) -> Result<tree_sitter::InputEdit, mlua::Error> {
+    Ok(tree_sitter::InputEdit {
+        start_byte: edit.get("start_byte")?,
+        old_end_byte: edit.get("old_end_byte")?,
+        new_end_byte: edit.get("new_end_byte")?,
+        start_position: read_point(edit.get("start_point")?)?,
+        old_end_position: read_point(edit.get("old_end_point")?)?,
+        new_end_position: read_point(edit.get("new_end_point")?)?,
+    })
+}
+
+fn node_table<'lua>(
+    l: &'lua Lua,
+    node: tree_sitter::Node,
+    src: &[u8],
+) -> Result<mlua::Table<'lua>, mlua::Error> {
+    let table = l.create_table()?;
+    table.set("type", node.kind())?;
+    table.set("named", node.is_named())?;
+    table.set("start_byte", node.start_byte())?;
+    table.set("end_byte", node.end_byte())?;
+    table.set("start_row", node.start_position().row)?;
+    table.set("start_col", node.start_position().column)?;
+    table.set("end_row", node.end_position().row)?;
+    table.set("end_col", node.end_position().column)?;
+    let text = String::from_utf8_lossy(&src[node.start_byte()..node.end_byte()]);
+    table.set("text", text.as_ref())?;
+    Ok(table)
+}
+
+/// The source slice matched by capture `index` in `m`, if any.
+fn capture_text<'t>(m: &tree_sitter::QueryMatch, index: u32, src: &'t [u8]) -> Option<&'t str> {
+    m.captures
+        .iter()
+        .find(|capture| capture.index == index)
+        .and_then(|capture| {
+            std::str::from_utf8(&src[capture.node.start_byte()..capture.node.end_byte()]).ok()
+        })
+}
+
+/// Evaluates one of the standard text-based predicates (`#eq?`, `#not-eq?`, `#match?`,
+/// `#not-match?`, `#any-of?`, `#not-any-of?`, …) against a match's captures.  These predicates
+/// are parsed by `tree_sitter::Query` into [`tree_sitter::TextPredicateCapture`]s, reachable via
+/// [`tree_sitter::Query::text_predicates`] — NOT [`tree_sitter::Query::general_predicates`],
+/// which only holds predicates the query parser doesn't understand (and which this bridge has no
+/// business trying to interpret).
+fn text_predicate_holds(
+    predicate: &tree_sitter::TextPredicateCapture,
+    m: &tree_sitter::QueryMatch,
+    src: &[u8],
+) -> bool {
+    use tree_sitter::TextPredicateCapture::*;
+    match predicate {
+        EqCapture(a, b, is_positive, _match_all_nodes, _case_insensitive) => {
+            let holds = match (capture_text(m, *a, src), capture_text(m, *b, src)) {
+                (Some(a), Some(b)) => a == b,
+                _ => false,
+            };
+            holds == *is_positive
+        }
+        EqString(index, value, is_positive, _match_all_nodes, _case_insensitive) => {
+            let holds = capture_text(m, *index, src) == Some(value.as_ref());
+            holds == *is_positive
+        }
+        MatchString(index, regex, is_positive, _match_all_nodes) => {
+            let holds = capture_text(m, *index, src)
+                .map(|text| regex.is_match(text))
+                .unwrap_or(false);
+            holds == *is_positive
+        }
+        AnyString(index, values, is_positive) => {
+            let holds = capture_text(m, *index, src)
+                .map(|text| values.iter().any(|value| value.as_ref() == text))
+                .unwrap_or(false);
+            holds == *is_positive
+        }
+    }
+}
+
+/// A compiled `tree_sitter` query, created via [`Module::compile_query`] and pushed to Lua as
+/// userdata so scripts can match tree-sitter's S-expression query language against a tree.
+pub struct CompiledQuery {
+    query: tree_sitter::Query,
+}
+
+impl CompiledQuery {
+    fn predicates_hold(&self, m: &tree_sitter::QueryMatch, src: &[u8]) -> bool {
+        self.query
+            .text_predicates(m.pattern_index)
+            .iter()
+            .all(|predicate| text_predicate_holds(predicate, m, src))
+    }
+
+    fn run(
+        &self,
+        l: &Lua,
+        tree_value: mlua::Value,
+        one_row_per_capture: bool,
+    ) -> Result<mlua::Value, mlua::Error> {
+        let (tree, src) = peek_tree_and_source(l, &tree_value)?;
+        let capture_names = self.query.capture_names();
+        let mut cursor = tree_sitter::QueryCursor::new();
+        let results = l.create_table()?;
+        let mut i = 1;
+
+        if one_row_per_capture {
+            for (m, capture_index) in cursor.captures(&self.query, tree.root_node(), src.as_slice())
+            {
+                if !self.predicates_hold(&m, &src) {
+                    continue;
+                }
+                let capture = m.captures[capture_index];
+                let row = l.create_table()?;
+                row.set(
+                    capture_names[capture.index as usize].as_str(),
+                    node_table(l, capture.node, &src)?,
+                )?;
+                results.set(i, row)?;
+                i += 1;
+            }
+        } else {
+            for m in cursor.matches(&self.query, tree.root_node(), src.as_slice()) {
+                if !self.predicates_hold(&m, &src) {
+                    continue;
+                }
+                let row = l.create_table()?;
+                for capture in m.captures {
+                    row.set(
+                        capture_names[capture.index as usize].as_str(),
+                        node_table(l, capture.node, &src)?,
+                    )?;
+                }
+                results.set(i, row)?;
+                i += 1;
+            }
+        }
+        Ok(mlua::Value::Table(results))
+    }
+}
+
+impl mlua::UserData for CompiledQuery {
+    fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("matches", |l, this, tree: mlua::Value| this.run(l, tree, false));
+        methods.add_method("captures", |l, this, tree: mlua::Value| this.run(l, tree, true));
+    }
+}
+
+/// A tree pushed to Lua as scope-local userdata by [`TreeWithSource::with_source_scoped`]; mlua
+/// invalidates it as soon as that call returns. Only exposes `root`, not `ltreesitter`'s native
+/// query bridge (see [`peek_tree_and_source`]).
+struct ScopedTree<'a> {
+    tree: Tree,
+    src: &'a [u8],
+}
+
+impl mlua::UserData for ScopedTree<'_> {
+    fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("root", |l, this, ()| {
+            node_table(l, this.tree.root_node(), this.src)
+        });
+    }
+}
+
+impl<'a> TreeWithSource<'a> {
+    /// Pushes this tree onto the Lua stack for the duration of `f`, without smuggling the
+    /// borrowed source buffer across the FFI boundary as an untracked light userdata pointer the
+    /// way [`IntoLua::into_lua`] does. The value handed to `f` is itself a
+    /// [`mlua::Lua::scope`]-local [`ScopedTree`]: mlua invalidates it the moment this call
+    /// returns, so the non-`'static` `src` can never be read after it goes out of scope, even if
+    /// `f` stashes the value somewhere that outlives the closure (a Lua global, an upvalue, …).
+    pub fn with_source_scoped<R>(
+        self,
+        l: &Lua,
+        f: impl FnOnce(mlua::Value) -> Result<R, mlua::Error>,
+    ) -> Result<R, mlua::Error> {
+        l.scope(|scope| {
+            let scoped = scope.create_nonstatic_userdata(ScopedTree {
+                tree: self.tree,
+                src: self.src,
+            })?;
+            f(mlua::Value::UserData(scoped))
+        })
+    }
+
+    /// Applies `edit` to this tree via [`tree_sitter::Tree::edit`], then reparses `new_src`
+    /// through `parser`, passing the edited tree as the `old_tree` argument so tree-sitter reuses
+    /// unchanged subtrees instead of reparsing from scratch. Consumes `self`: the old tree is
+    /// gone once this returns, and the caller is left with only the fresh
+    /// [`TreeWithSource`] over `new_src`.
+    pub fn edit(
+        self,
+        parser: &mut tree_sitter::Parser,
+        edit: &tree_sitter::InputEdit,
+        new_src: &'a [u8],
+    ) -> Result<TreeWithSource<'a>, mlua::Error> {
+        let mut edited_tree = self.tree;
+        edited_tree.edit(edit);
+        let tree = parser
+            .parse(new_src, Some(&edited_tree))
+            .ok_or_else(|| mlua::Error::RuntimeError("failed to reparse source".to_owned()))?;
+        Ok(tree.with_source(new_src))
+    }
+}
+
+/// Options controlling how [`SerializableTree::new`] walks a tree.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SerializeOptions {
+    prune_anonymous: bool,
+    max_depth: Option<usize>,
+}
+
+impl SerializeOptions {
+    /// The default options: keep every node, and walk to whatever depth the tree goes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// If set, anonymous (unnamed) nodes — and their subtrees — are left out of the walk.
+    pub fn prune_anonymous(mut self, prune_anonymous: bool) -> Self {
+        self.prune_anonymous = prune_anonymous;
+        self
+    }
+
+    /// If set, nodes at depth `max_depth` (the root is at depth 0) are emitted with an empty
+    /// `children` table, and nodes below them are not emitted at all, so that huge trees don't
+    /// blow the Lua stack.
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+}
+
+/// A snapshot of a [`tree_sitter::Tree`] as plain data: nested nodes with no Lua or tree-sitter
+/// machinery attached, convertible to Lua tables and, with the `serialize` feature, to `serde`.
+#[cfg_attr(feature = "serialize", derive(serde::Serialize))]
+pub struct SerializableTree {
+    root: SerializableNode,
+}
+
+#[cfg_attr(feature = "serialize", derive(serde::Serialize))]
+struct SerializableNode {
+    r#type: String,
+    named: bool,
+    start_byte: usize,
+    end_byte: usize,
+    start_row: usize,
+    start_col: usize,
+    end_row: usize,
+    end_col: usize,
+    field: Option<String>,
+    text: Option<String>,
+    children: Vec<SerializableNode>,
+}
+
+struct OpenNode {
+    kind: String,
+    named: bool,
+    start_byte: usize,
+    end_byte: usize,
+    start_row: usize,
+    start_col: usize,
+    end_row: usize,
+    end_col: usize,
+    field: Option<String>,
+}
+
+impl OpenNode {
+    fn capture(cursor: &tree_sitter::TreeCursor) -> Self {
+        let node = cursor.node();
+        let start = node.start_position();
+        let end = node.end_position();
+        OpenNode {
+            kind: node.kind().to_owned(),
+            named: node.is_named(),
+            start_byte: node.start_byte(),
+            end_byte: node.end_byte(),
+            start_row: start.row,
+            start_col: start.column,
+            end_row: end.row,
+            end_col: end.column,
+            field: cursor.field_name().map(str::to_owned),
+        }
+    }
+
+    fn finish(self, children: Vec<SerializableNode>, src: &[u8]) -> SerializableNode {
+        // Only leaves carry `text`; an interior node's source range is already covered by its
+        // children, and duplicating it would bloat the table for no benefit.
+        let text = children.is_empty().then(|| {
+            String::from_utf8_lossy(&src[self.start_byte..self.end_byte]).into_owned()
+        });
+        SerializableNode {
+            r#type: self.kind,
+            named: self.named,
+            start_byte: self.start_byte,
+            end_byte: self.end_byte,
+            start_row: self.start_row,
+            start_col: self.start_col,
+            end_row: self.end_row,
+            end_col: self.end_col,
+            field: self.field,
+            text,
+            children,
+        }
+    }
+}
+
+fn descend_to_first_kept_child(
+    cursor: &mut tree_sitter::TreeCursor,
+    options: &SerializeOptions,
+) -> bool {
+    if !cursor.goto_first_child() {
+        return false;
+    }
+    loop {
+        if !options.prune_anonymous || cursor.node().is_named() {
+            return true;
+        }
+        if !cursor.goto_next_sibling() {
+            cursor.goto_parent();
+            return false;
+        }
+    }
+}
+
+fn advance_to_next_kept_sibling(
+    cursor: &mut tree_sitter::TreeCursor,
+    options: &SerializeOptions,
+) -> bool {
+    loop {
+        if !cursor.goto_next_sibling() {
+            return false;
+        }
+        if !options.prune_anonymous || cursor.node().is_named() {
+            return true;
+        }
+    }
+}
+
+impl SerializableTree {
+    /// Walks `tree` (whose source is `src`) into a [`SerializableTree`], applying `options` to
+    /// control pruning and depth.  Walks with an explicit work stack rather than Rust recursion,
+    /// since the source tree may be arbitrarily deep.
+    pub fn new(tree: &tree_sitter::Tree, src: &[u8], options: SerializeOptions) -> Self {
+        let mut cursor = tree.walk();
+        let mut frames: Vec<(OpenNode, Vec<SerializableNode>)> =
+            vec![(OpenNode::capture(&cursor), Vec::new())];
+        let mut depth = 0usize;
+
+        loop {
+            let within_depth = options.max_depth.map_or(true, |max| depth < max);
+            if within_depth && descend_to_first_kept_child(&mut cursor, &options) {
+                depth += 1;
+                frames.push((OpenNode::capture(&cursor), Vec::new()));
+                continue;
+            }
+
+            loop {
+                let (open, children) = frames.pop().expect("work stack is never empty here");
+                let built = open.finish(children, src);
+                match frames.last_mut() {
+                    None => return SerializableTree { root: built },
+                    Some((_, parent_children)) => parent_children.push(built),
+                }
+                if advance_to_next_kept_sibling(&mut cursor, &options) {
+                    frames.push((OpenNode::capture(&cursor), Vec::new()));
+                    break;
+                }
+                if !cursor.goto_parent() {
+                    unreachable!("cursor ran out of ancestors before the work stack did");
+                }
+                depth -= 1;
+            }
+        }
+    }
+}
+
+/// A [`SerializableNode`] mid-conversion to a Lua table: its own scalar fields already pulled out,
+/// its children still an iterator to be converted and appended to `children_table` one at a time.
+struct NodeFrame<'lua> {
+    r#type: String,
+    named: bool,
+    start_byte: usize,
+    end_byte: usize,
+    start_row: usize,
+    start_col: usize,
+    end_row: usize,
+    end_col: usize,
+    field: Option<String>,
+    text: Option<String>,
+    remaining_children: std::vec::IntoIter<SerializableNode>,
+    children_table: mlua::Table<'lua>,
+    children_so_far: usize,
+}
+
+impl<'lua> NodeFrame<'lua> {
+    fn new(l: &'lua Lua, node: SerializableNode) -> Result<Self, mlua::Error> {
+        Ok(NodeFrame {
+            r#type: node.r#type,
+            named: node.named,
+            start_byte: node.start_byte,
+            end_byte: node.end_byte,
+            start_row: node.start_row,
+            start_col: node.start_col,
+            end_row: node.end_row,
+            end_col: node.end_col,
+            field: node.field,
+            text: node.text,
+            remaining_children: node.children.into_iter(),
+            children_table: l.create_table()?,
+            children_so_far: 0,
+        })
+    }
+
+    fn finish(self, l: &'lua Lua) -> Result<mlua::Table<'lua>, mlua::Error> {
+        let table = l.create_table()?;
+        table.set("type", self.r#type)?;
+        table.set("named", self.named)?;
+        table.set("start_byte", self.start_byte)?;
+        table.set("end_byte", self.end_byte)?;
+        table.set("start_row", self.start_row)?;
+        table.set("start_col", self.start_col)?;
+        table.set("end_row", self.end_row)?;
+        table.set("end_col", self.end_col)?;
+        table.set("field", self.field)?;
+        table.set("text", self.text)?;
+        table.set("children", self.children_table)?;
+        Ok(table)
+    }
+}
+
+/// Converts a [`SerializableNode`] into nested Lua tables with an explicit work stack rather than
+/// Rust recursion, for the same reason [`SerializableTree::new`] walks the source tree with one:
+/// the tree may be arbitrarily deep, and `max_depth` is only an opt-in bound, not a guarantee.
+fn node_into_lua<'lua>(
+    l: &'lua Lua,
+    root: SerializableNode,
+) -> Result<mlua::Table<'lua>, mlua::Error> {
+    let mut frames = vec![NodeFrame::new(l, root)?];
+
+    loop {
+        let frame = frames.last_mut().expect("work stack is never empty here");
+        match frame.remaining_children.next() {
+            Some(child) => frames.push(NodeFrame::new(l, child)?),
+            None => {
+                let frame = frames.pop().expect("just matched on the same frame");
+                let built = frame.finish(l)?;
+                match frames.last_mut() {
+                    None => return Ok(built),
+                    Some(parent) => {
+                        parent.children_so_far += 1;
+                        parent.children_table.set(parent.children_so_far, built)?;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl mlua::IntoLua<'_> for SerializableTree {
+    fn into_lua(self, l: &Lua) -> Result<mlua::Value, mlua::Error> {
+        Ok(mlua::Value::Table(node_into_lua(l, self.root)?))
+    }
+}
+
+impl<'a> TreeWithSource<'a> {
+    /// Materializes this tree as ordinary nested Lua tables (see [`SerializableTree`]) rather
+    /// than pushing native `ltreesitter` userdata, so callers that don't link the `ltreesitter` C
+    /// module can still walk the result, and so the tree can round-trip through `mlua`'s serde
+    /// support (the `serialize` feature) to JSON, MessagePack, etc.
+    pub fn into_lua_table(
+        &self,
+        l: &Lua,
+        options: SerializeOptions,
+    ) -> Result<mlua::Value, mlua::Error> {
+        SerializableTree::new(&self.tree, self.src, options).into_lua(l)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -135,4 +859,169 @@ mod tests {
         )?;
         Ok(())
     }
+
+    #[test]
+    fn take_tree_recovers_tree_and_consumes_the_lua_side() -> Result<(), anyhow::Error> {
+        let code = br#"
+          def double(x):
+              return x * 2
+        "#;
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language())?;
+        let parsed = parser.parse(code, None).expect("Cannot parse Python code");
+        let mut l = Lua::new();
+        l.open_ltreesitter(false)?;
+        l.globals().set("parsed", parsed.with_source(code))?;
+
+        let value: mlua::Value = l.globals().get("parsed")?;
+        let OwnedTree(tree) = l.globals().get("parsed")?;
+        assert_eq!(
+            tree.root_node().kind(),
+            "module",
+            "expected module as root of the recovered tree"
+        );
+
+        let err = value
+            .take_tree(&l)
+            .expect_err("tree should already have been taken by the FromLua conversion above");
+        assert!(
+            err.to_string().to_lowercase().contains("not an ltreesitter tree")
+                || err.to_string().to_lowercase().contains("destructed"),
+            "expected taking the tree a second time to fail, got: {err}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn scoped_tree_is_invalidated_once_scope_ends() -> Result<(), anyhow::Error> {
+        let code = b"x = 1";
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language())?;
+        let parsed = parser.parse(code, None).expect("Cannot parse Python code");
+        let mut l = Lua::new();
+        l.open_ltreesitter(false)?;
+
+        // Stash the scoped tree in a Lua global from inside the closure, so that the reference
+        // lives on past the closure's own lexical scope.
+        parsed.with_source(code).with_source_scoped(&l, |tree| {
+            l.globals().set("escaped", tree)?;
+            Ok(())
+        })?;
+
+        let err = l
+            .check("return escaped:root()")
+            .expect_err("tree should no longer be usable once with_source_scoped has returned");
+        assert!(
+            err.to_string().to_lowercase().contains("destructed"),
+            "expected a destructed-userdata error, got: {err}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn query_predicates_filter_non_matching_captures() -> Result<(), anyhow::Error> {
+        let code = br#"
+          def double(x):
+              return x * 2
+        "#;
+        let language = tree_sitter_python::language();
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(language.clone())?;
+        let parsed = parser.parse(code, None).expect("Cannot parse Python code");
+
+        let mut l = Lua::new();
+        l.open_ltreesitter(false)?;
+        let query = l.compile_query(&language, r#"((identifier) @id (#eq? @id "double"))"#)?;
+        l.globals().set("tree", parsed.with_source(code))?;
+        l.globals().set("query", query)?;
+        l.check(
+            r#"
+              local results = query:matches(tree)
+              assert(#results == 1, "expected #eq? to filter down to a single match, got " .. #results)
+              assert(results[1].id.text == "double", "expected the surviving match's text to be 'double'")
+            "#,
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn parser_parses_the_same_source_repeatedly() -> Result<(), anyhow::Error> {
+        let mut l = Lua::new();
+        l.open_ltreesitter(false)?;
+        l.register_language("python", tree_sitter_python::language());
+        l.globals().set("parser", l.parser("python"))?;
+        l.check(
+            r#"
+              -- Parse the same source many times; each `ParsedTree` owns its own copy of the
+              -- source, which is freed along with the tree once Lua collects it.
+              for _ = 1, 1000 do
+                local tree = parser:parse("def double(x):\n    return x * 2\n")
+                assert(tree:root().type == "module", "expected module as root of tree")
+              end
+            "#,
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn tree_edit_reparses_incrementally_and_consumes_old_tree() -> Result<(), anyhow::Error> {
+        let mut l = Lua::new();
+        l.open_ltreesitter(false)?;
+        l.register_language("python", tree_sitter_python::language());
+        l.globals().set("parser", l.parser("python"))?;
+        l.check(
+            r#"
+              local old_src = "def f(x):\n    return x\n"
+              tree = parser:parse(old_src)
+
+              local new_src = "def ff(x):\n    return x\n"
+              local new_tree = tree:edit({
+                start_byte = 4,
+                old_end_byte = 4,
+                new_end_byte = 5,
+                start_point = {row = 0, column = 4},
+                old_end_point = {row = 0, column = 4},
+                new_end_point = {row = 0, column = 5},
+              }, new_src)
+
+              local root = new_tree:root()
+              assert(root.type == "module", "expected module as root of reparsed tree")
+              assert(root.text == new_src, "expected reparsed tree's root to cover the new source")
+            "#,
+        )?;
+
+        let err = l
+            .check("return tree:root()")
+            .expect_err("old tree should be consumed by edit, not merely cloned");
+        assert!(
+            err.to_string().to_lowercase().contains("destructed"),
+            "expected a destructed-userdata error, got: {err}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn serializable_tree_converts_nested_structure_to_lua_tables() -> Result<(), anyhow::Error> {
+        let code = br#"
+          def double(x):
+              return x * 2
+        "#;
+        let mut parser = tree_sitter::Parser::new();
+        parser.set_language(tree_sitter_python::language())?;
+        let parsed = parser.parse(code, None).expect("Cannot parse Python code");
+        let mut l = Lua::new();
+        l.globals().set(
+            "tree",
+            parsed.with_source(code).into_lua_table(&l, SerializeOptions::new())?,
+        )?;
+        l.check(
+            r#"
+              assert(tree.type == "module", "expected module as root of tree")
+              assert(#tree.children >= 1, "expected the module to have at least one child")
+              local func = tree.children[1]
+              assert(func.type == "function_definition", "expected a function_definition child, got " .. func.type)
+            "#,
+        )?;
+        Ok(())
+    }
 }